@@ -1,5 +1,5 @@
 use std::fmt::Write;
-use std::collections::{VecDeque, HashMap};
+use std::collections::{VecDeque, HashMap, HashSet};
 use std::cell::RefCell;
 use failure_derive::*;
 use crate::rom::{Rom, Map};
@@ -7,45 +7,101 @@ use crate::rom::{Rom, Map};
 pub mod datatype;
 pub mod bc;
 mod globals;
+mod unify;
 pub mod parse;
 
 use datatype::*;
 use parse::{ast::*, Unparse};
+use unify::{TyVarKey, TyVarTable};
+use ena::unify::UnifyKey;
 
-pub fn decompile_map(map: Map, _rom: &mut Rom) -> Result<String, Error> {
+pub fn decompile_map(map: Map, rom: &mut Rom) -> Result<(String, Diagnostics), Error> {
     let mut scope        = Scope::new();
     let mut declarations = Vec::new();
+    let mut diagnostics: Diagnostics = Vec::new();
 
     // Bring global methods into scope
     for (ptr, name, ty) in &*globals::METHODS {
         scope.insert_ptr(*ptr, name.to_string(), ty.clone());
     }
 
-    {
-        let (loc, bc) = map.main_fun;
+    let (main_loc, main_bc) = map.main_fun;
+    let main_ptr: u32 = main_loc.into();
 
-        // Main function takes no arguments
-        scope.insert_ptr(loc.into(), "main".to_string(), DataType::Fun(vec![]));
+    // Main starts out taking no arguments; `update_signature` fills in its
+    // captured parameters once it's been decompiled.
+    scope.insert_ptr(main_ptr, "main".to_string(), DataType::Fun(vec![]));
+
+    // Any script referenced by a `callback = myscript` assignment or passed as
+    // a function-pointer argument is only discovered once whatever references
+    // it has been decompiled, so we work through a queue seeded with `main`
+    // rather than decompiling just that one function.
+    let mut visited: HashSet<u32> = HashSet::new();
+    let mut worklist: VecDeque<u32> = VecDeque::new();
+    let mut main_bc = Some(main_bc);
+
+    visited.insert(main_ptr);
+    worklist.push_back(main_ptr);
+
+    while let Some(ptr) = worklist.pop_front() {
+        let bc = match main_bc.take() {
+            Some(bc) => bc,
+            None     => rom.read_bytecode(ptr)?,
+        };
 
         // Decompile the bytecode
         let mut decl = Declaration::Fun {
-            name:      IdentifierOrPointer::Pointer(loc.into()),
+            name:      IdentifierOrPointer::Pointer(ptr),
             arguments: Vec::new(),
             block:     bc.decompile(&mut scope)?,
         };
 
+        let mut captured = Vec::new();
+
         for mut block in decl.inner_blocks_mut() {
-            // TODO: decompile pointers within, followed by a type inference pass
+            fix_call_arg_capture(&mut block, &mut scope, &mut diagnostics);
+            infer_datatypes(&mut block, &mut scope, &mut diagnostics);
+
+            for pointer in referenced_script_pointers(&mut block, &scope) {
+                if visited.insert(pointer) {
+                    // Insert a generated name now so that any other reference
+                    // to this pointer (including recursive calls) resolves
+                    // symbolically, then decompile it once we get to it.
+                    let name = format!("{}_{:X}", globals::FUN_STR, pointer);
+                    scope.insert_ptr(pointer, name, DataType::Fun(vec![]));
+                    worklist.push_back(pointer);
+                }
+            }
 
-            fix_call_arg_capture(&mut block, &scope)?;
-            infer_datatypes(&mut block, &mut scope)?;
+            captured.extend(captured_param_names(&mut block));
         }
 
-        // TODO: replace decl.arguments with the types that were inferred
+        update_signature(&mut decl, captured, &mut scope);
 
         declarations.push(decl);
     }
 
+    // A caller can be visited before a callee it references has even been
+    // discovered, in which case its calls were captured against that
+    // callee's placeholder `DataType::Fun(vec![])` signature rather than the
+    // real one. Now that every pointer in the worklist has been decompiled
+    // and every signature is final, re-check every function once more so
+    // those call sites -- and the types inferred for whatever of this
+    // function's own captured parameters feed them -- settle on the types
+    // the rest of the map actually agrees on.
+    for decl in declarations.iter_mut() {
+        let mut captured = Vec::new();
+
+        for mut block in decl.inner_blocks_mut() {
+            fix_call_arg_capture(&mut block, &mut scope, &mut diagnostics);
+            infer_datatypes(&mut block, &mut scope, &mut diagnostics);
+
+            captured.extend(captured_param_names(&mut block));
+        }
+
+        update_signature(decl, captured, &mut scope);
+    }
+
     // Unparse everything
     let mut out = String::new();
 
@@ -53,7 +109,150 @@ pub fn decompile_map(map: Map, _rom: &mut Rom) -> Result<String, Error> {
         writeln!(out, "{}", declaration.unparse(&scope)).unwrap();
     }
 
-    Ok(out)
+    Ok((out, diagnostics))
+}
+
+/// Scans `block` (and its inner blocks) for references to not-yet-decompiled
+/// scripts: either a `callback = myscript` assignment to a variable whose
+/// datatype is a function, or a raw pointer passed as an argument in a
+/// position whose declared parameter type is `DataType::Fun`/`DataType::Asm`.
+/// Returns every such pointer found.
+fn referenced_script_pointers(block: &mut Vec<Statement>, scope: &Scope) -> Vec<u32> {
+    let mut pointers = Vec::new();
+
+    for stmt in block.iter_mut() {
+        match stmt {
+            Statement::VarAssign { identifier: Identifier(name), expression } => {
+                match scope.lookup_name(name) {
+                    Some(DataType::Fun(_)) | Some(DataType::Asm(_)) => {
+                        if let Expression::Pointer(ptr) = expression.clone().into_inner() {
+                            pointers.push(ptr);
+                        }
+                    },
+
+                    _ => (),
+                }
+            },
+
+            Statement::MethodCall { method, arguments, .. } => match method.lookup(scope) {
+                Some((_, &DataType::Asm(ref arg_types))) |
+                Some((_, &DataType::Fun(ref arg_types))) => {
+                    for (ty, arg) in arg_types.iter().zip(arguments.iter()) {
+                        match (ty, arg.clone().into_inner()) {
+                            (DataType::Fun(_), Expression::Pointer(ptr)) |
+                            (DataType::Asm(_), Expression::Pointer(ptr)) => pointers.push(ptr),
+
+                            _ => (),
+                        }
+                    }
+                },
+
+                _ => (),
+            },
+
+            _ => (),
+        }
+
+        for mut inner_block in stmt.inner_blocks_mut() {
+            pointers.extend(referenced_script_pointers(&mut inner_block, scope));
+        }
+    }
+
+    pointers
+}
+
+/// Is `name` a synthetic `FUNWORD_n` identifier?
+fn is_funword(name: &str) -> bool {
+    name.starts_with(&format!("{}_", globals::FUNWORD_STR))
+}
+
+/// Is `name` a synthetic `FUNFLAG_n` identifier? Flags are always captured
+/// `Bool`s, by convention -- there's no need to infer that.
+fn is_funflag(name: &str) -> bool {
+    name.starts_with(&format!("{}_", globals::FUNFLAG_STR))
+}
+
+/// Returns the datatype `scope` has for `name`, falling back to the implicit
+/// `Bool` convention for `FUNFLAG_n` identifiers that haven't been otherwise
+/// declared.
+fn lookup_captured_datatype(scope: &Scope, name: &str) -> Option<DataType> {
+    match scope.lookup_name(name).cloned() {
+        Some(datatype)           => Some(datatype),
+        None if is_funflag(name) => Some(DataType::Bool),
+        None                     => None,
+    }
+}
+
+/// Collects the name of every captured `FUNWORD_n`/`FUNFLAG_n` identifier
+/// referenced within `block` (and its inner blocks), in the order they're
+/// found. These are the function's implicit parameters -- reading their
+/// inferred types back out of `Scope` gives us the function's real signature.
+fn captured_param_names(block: &mut Vec<Statement>) -> Vec<String> {
+    let mut names = Vec::new();
+
+    fn push_if_captured(names: &mut Vec<String>, expression: &Expression) {
+        if let Expression::Identifier(Identifier(name)) = expression {
+            if is_funword(name) || is_funflag(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+
+    for stmt in block.iter_mut() {
+        match stmt {
+            Statement::VarDeclare { expression: Some(expression), .. } =>
+                push_if_captured(&mut names, &expression.borrow()),
+
+            Statement::VarAssign { expression, .. } =>
+                push_if_captured(&mut names, &expression.borrow()),
+
+            Statement::MethodCall { arguments, .. } =>
+                for arg in arguments.iter() {
+                    push_if_captured(&mut names, &arg.borrow());
+                },
+
+            _ => (),
+        }
+
+        for mut inner_block in stmt.inner_blocks_mut() {
+            names.extend(captured_param_names(&mut inner_block));
+        }
+    }
+
+    names
+}
+
+/// Dedups `captured` (preserving first-seen order, which lines up with the
+/// word/flag slot each name was synthesized for) and writes the resulting
+/// typed parameter list back into `decl.arguments`, then updates `decl`'s own
+/// `DataType::Fun` entry in `scope` to match so a later re-check of its
+/// callers agrees on the same signature.
+fn update_signature(decl: &mut Declaration, captured: Vec<String>, scope: &mut Scope) {
+    let mut seen = HashSet::new();
+    let captured: Vec<String> = captured.into_iter().filter(|name| seen.insert(name.clone())).collect();
+
+    let arguments: Vec<(Identifier, DataType)> = captured.into_iter()
+        .map(|name| {
+            let datatype = lookup_captured_datatype(scope, &name).unwrap_or(DataType::Any);
+            (Identifier(name), datatype)
+        })
+        .collect();
+
+    let ptr = match decl {
+        Declaration::Fun { name: IdentifierOrPointer::Pointer(ptr), arguments: decl_arguments, .. } => {
+            *decl_arguments = arguments.clone();
+            Some(*ptr)
+        },
+
+        _ => None,
+    };
+
+    if let Some(ptr) = ptr {
+        if let Some(name) = scope.lookup_ptr(ptr).map(str::to_string) {
+            let argument_types = arguments.into_iter().map(|(_, ty)| ty).collect();
+            scope.insert_name(name, DataType::Fun(argument_types));
+        }
+    }
 }
 
 /// Paper Mario function calls capture their environment -- that is, they take
@@ -74,169 +273,401 @@ pub fn decompile_map(map: Map, _rom: &mut Rom) -> Result<String, Error> {
 ///
 /// Note that this transformation should only be applied to decompiled ASTs, not
 /// those the user gives us; this should be a missing-method-arg error.
-fn fix_call_arg_capture(block: &mut Vec<Statement>, scope: &Scope) -> Result<(), Error> {
+fn fix_call_arg_capture(block: &mut Vec<Statement>, scope: &mut Scope, diagnostics: &mut Diagnostics) {
     for stmt in block.iter_mut() {
         if let Statement::MethodCall { method, arguments, .. } = stmt {
-            // Only functions capture - asm methods take args normally.
-            if let Some((_, DataType::Fun(argument_types))) = method.lookup(scope) {
-                assert_eq!(arguments.len(), 0);
+            match method.lookup(scope) {
+                // Functions capture - synthesize a FunWord identifier per
+                // captured parameter, or a FunFlag if the parameter's a Bool.
+                // Words and flags live in separate namespaces, so each has
+                // its own running index.
+                Some((_, DataType::Fun(argument_types))) => {
+                    // Clone out of the immutable borrow `method.lookup`
+                    // returned so we're free to write the captured params'
+                    // types back into `scope` as we go.
+                    let argument_types = argument_types.clone();
+
+                    // This may run more than once for the same call site (a
+                    // caller re-checked once every signature in the map is
+                    // known), so start from a clean slate rather than
+                    // assuming it's never been captured before.
+                    arguments.clear();
+
+                    let mut words = 0;
+                    let mut flags = 0;
+
+                    for ty in argument_types.iter() {
+                        let name = match ty {
+                            DataType::Bool => {
+                                let name = format!("{}_{:X}", globals::FUNFLAG_STR, flags);
+                                flags += 1;
+                                name
+                            },
 
-                for (n, _) in argument_types.iter().enumerate() {
-                    // TODO: see if FunFlags should be captured if the arg type
-                    //       is DataType::Bool
+                            _ => {
+                                let name = format!("{}_{:X}", globals::FUNWORD_STR, words);
+                                words += 1;
+                                name
+                            },
+                        };
 
-                    let name = format!("{}_{:X}", globals::FUNWORD_STR, n);
+                        // Record the parameter's declared type against the
+                        // synthesized identifier, so a FunWord keeps the
+                        // concrete type it was captured for instead of
+                        // falling back to `Any` when its signature is read
+                        // back out later (FunFlags already default to Bool,
+                        // but recording it here keeps both paths the same).
+                        scope.insert_name(name.clone(), ty.clone());
 
-                    arguments.push(RefCell::new(Expression::Identifier(Identifier(name))));
-                }
+                        arguments.push(RefCell::new(Expression::Identifier(Identifier(name))));
+                    }
+                },
+
+                // Asm methods take their args normally - nothing to capture.
+                Some((_, DataType::Asm(_))) => (),
+
+                // We don't know this call's signature at all, so we can't
+                // tell what it captures. Record it and move on rather than
+                // aborting the whole map.
+                None => diagnostics.push(Error::UnknownMethodSignature { method: method.clone() }),
             }
         }
 
         // Fix inner blocks, too.
         for mut inner_block in stmt.inner_blocks_mut() {
-            fix_call_arg_capture(&mut inner_block, &scope)?;
+            fix_call_arg_capture(&mut inner_block, scope, diagnostics);
         }
     }
+}
 
-    Ok(())
+/// Infers the types of `Any`-declared variables in a single pass, using a
+/// union-find table over type variables (in the style of `ena`, as used by
+/// rust-analyzer's `ra_hir_ty`) instead of repeatedly re-walking the block
+/// until nothing changes. Every `Any` declaration becomes a `DataType::Var`
+/// backed by a `TyVarKey`; equality constraints between variables (and
+/// between a variable and a concrete type) are unified as we go, so two
+/// variables that are only transitively related (`a = b; b = some_bool_call()`)
+/// end up sharing a root regardless of which statement we see first.
+fn infer_datatypes(block: &mut Vec<Statement>, scope: &mut Scope, diagnostics: &mut Diagnostics) {
+    let mut vars = TyVarTable::new();
+
+    collect_constraints(block, scope, &mut vars, diagnostics);
+    resolve_vars(block, scope, &mut vars);
 }
 
-/// Performs a single type inference pass. Replaces 'any' declarations and their
-/// respective scope mappings if their types can be inferred.
-fn infer_datatypes(block: &mut Vec<Statement>, mut scope: &mut Scope) -> Result<(), Error> {
-    let mut made_inferences = true;
-
-    // This works like a bubble sort -- keep inferring types until we can't.
-    while made_inferences {
-        made_inferences = false;
-
-        // We only insert inferred types into scope after the interator
-        // finishes, because we perform lookups in there and the borrow checker
-        // would scream at us for mutating it while we had an immutable ref.
-        let mut inferred: Vec<(String, DataType)> = Vec::new();
-
-        // We iterate in reverse so we can figure out the types before we see their
-        // declaration statement (once we do see it, we update its type).
-        for stmt in block.iter_mut().rev() {
-            match stmt {
-                // Update var declarations with inferred types.
-                Statement::VarDeclare { datatype, identifier: Identifier(name), expression } => {
-                    match scope.lookup_name_depth(&name, 0) {
-                        Some(inferred_datatype) => match datatype.replace(DataType::Any) {
-                            // User has left it up to the compiler to infer the
-                            // type, so lets do that.
-                            DataType::Any => {
-                                datatype.replace(inferred_datatype.clone());
-
-                                if let DataType::Bool = inferred_datatype {
-                                    // Update int literal to a bool literal.
-                                    if let Some(expression) = expression {
-                                        if let Expression::LiteralInt(v) = expression.clone().into_inner() {
-                                            expression.replace(Expression::LiteralBool(v == 1));
-                                        }
-                                    }
-                                }
-                            },
+/// Walks `block` (and its inner blocks) exactly once, allocating a `TyVarKey`
+/// for every `Any` declaration and unifying it with whatever constrains it:
+/// the right-hand-side of its initializer/assignment, or the declared type of
+/// the corresponding parameter at a call site. A conflicting unification is
+/// recorded in `diagnostics` and the variable is poisoned (left `Any`)
+/// instead of aborting the whole block.
+fn collect_constraints(block: &mut Vec<Statement>, scope: &mut Scope, vars: &mut TyVarTable, diagnostics: &mut Diagnostics) {
+    for stmt in block.iter_mut() {
+        match stmt {
+            Statement::VarDeclare { datatype, identifier: Identifier(name), expression } => {
+                match datatype.borrow().clone() {
+                    // User has left it up to the compiler to infer the type,
+                    // so give it a fresh type variable.
+                    DataType::Any => {
+                        let key = vars.new_var();
+                        datatype.replace(DataType::Var(key.index()));
+                        scope.insert_name(name.clone(), DataType::Var(key.index()));
+
+                        if let Some(expression) = expression {
+                            unify_expr(key, &expression.borrow(), scope, vars, name, diagnostics);
+                        }
+                    },
 
-                            // User declared the type but we inferred its use
-                            // as some other type. Error.
-                            datatype => return Err(Error::VarDeclareTypeMismatch {
-                                identifier:        name.clone(),
-                                declared_datatype: datatype,
-                                inferred_datatype: inferred_datatype.clone(),
-                            }),
-                        },
-
-                        // The variable is declared here but isn't in the current
-                        // scope, so add it to the scope after this pass.
-                        None => inferred.push((name.clone(), match expression {
-                            Some(expression) => expression.borrow().infer_datatype(&scope),
-                            None             => DataType::Any,
-                        })),
-                    }
-                },
+                    // Already fully typed; keep it current in scope for
+                    // statements further down the block.
+                    declared => { scope.insert_name(name.clone(), declared); },
+                }
+            },
 
-                // Infer left-hand-type by the right-hand-type of var assignments.
-                Statement::VarAssign { identifier: Identifier(name), expression } => {
-                    match scope.lookup_name(name) {
-                        // We only need to infer Any (i.e. unknown) types.
-                        Some(DataType::Any)
-                            => inferred.push((name.clone(), expression.borrow().infer_datatype(scope))),
+            // Unify the left-hand-side's variable (if it has one) with the
+            // right-hand-side of the assignment.
+            Statement::VarAssign { identifier: Identifier(name), expression } => {
+                if let Some(DataType::Var(n)) = scope.lookup_name(name).cloned() {
+                    unify_expr(TyVarKey::from_index(n), &expression.borrow(), scope, vars, name, diagnostics);
+                }
+            },
+
+            // Unify each identifier argument's variable with the declared
+            // type of the parameter it's passed to.
+            Statement::MethodCall { method, arguments, .. } => {
+                let arg_types = match method.lookup(scope) {
+                    Some((_, DataType::Asm(arg_types))) |
+                    Some((_, DataType::Fun(arg_types))) => Some(arg_types.clone()),
+                    _ => None,
+                };
+
+                if let Some(arg_types) = arg_types {
+                    for (ty, arg) in arg_types.iter().zip(arguments.iter()) {
+                        if let Expression::Identifier(Identifier(name)) = arg.borrow().clone() {
+                            match scope.lookup_name(&name).cloned() {
+                                Some(DataType::Var(n)) =>
+                                    unify_with_type(TyVarKey::from_index(n), ty.clone(), vars, &name, diagnostics),
+
+                                // A call argument needs a definitely-set
+                                // value; flag it if all we can offer is one
+                                // that's only conditionally set.
+                                Some(DataType::Optional(_)) =>
+                                    diagnostics.push(Error::PossiblyUnsetVariable { identifier: name }),
 
-                        // Update int literal to bool literal.
-                        Some(DataType::Bool) => {
-                            if let Expression::LiteralInt(v) = expression.clone().into_inner() {
-                                expression.replace(Expression::LiteralBool(v == 1));
+                                _ => (),
                             }
-                        },
-
-                        _ => (),
+                        }
                     }
-                },
+                }
+            },
 
-                // Infer types of method call arguments.
-                Statement::MethodCall { method, arguments, .. } => match method.lookup(scope) {
-                    Some((_, &DataType::Asm(ref arg_types))) |
-                    Some((_, &DataType::Fun(ref arg_types))) => {
-                        for (ty, arg) in arg_types.iter().zip(arguments.iter()) {
-                            match arg.clone().into_inner() {
-                                // Only identifiers influence type inference.
-                                Expression::Identifier(Identifier(name)) => {
-                                    // We only need to infer Any (i.e. unknown) types.
-                                    if let Some(DataType::Any) = scope.lookup_name(&name) {
-                                        // Define the inferred type!
-                                        inferred.push((name.clone(), ty.clone()));
-                                    }
-                                },
-
-                                // Update int literal to bool literal.
-                                Expression::LiteralInt(v) => {
-                                    if let DataType::Bool = ty {
-                                        arg.replace(Expression::LiteralBool(v == 1));
-                                    }
-                                },
+            _ => (),
+        }
 
-                                _ => (),
+        // Each of this statement's inner blocks (an if/else's two bodies, a
+        // loop's single body, ...) is a branch that may or may not run;
+        // isolate what it infers in its own scope layer, then join the
+        // branches back together so a variable only set on some of them
+        // comes back out `Optional` rather than silently picking one
+        // branch's type.
+        let mut branches = Vec::new();
+
+        for mut inner_block in stmt.inner_blocks_mut() {
+            scope.push();
+            collect_constraints(&mut inner_block, scope, vars, diagnostics);
+            branches.push(scope.pop().expect("just pushed a layer").1);
+        }
+
+        if !branches.is_empty() {
+            join_branches(scope, branches, vars);
+        }
+    }
+}
+
+/// Joins the per-branch local scope layers captured after walking each of a
+/// statement's inner blocks back into the enclosing scope. `vars` resolves
+/// any not-yet-substituted `DataType::Var` a branch left behind, so two
+/// branches that reach the same concrete type through different variables
+/// are recognised as agreeing instead of being compared by raw var key.
+fn join_branches(scope: &mut Scope, branches: Vec<HashMap<String, DataType>>, vars: &mut TyVarTable) {
+    let mut names: HashSet<String> = HashSet::new();
+
+    for branch in &branches {
+        names.extend(branch.keys().cloned());
+    }
+
+    // Exactly one of an if/else's two branches always runs, so there's no
+    // implicit "took none of these branches" path to account for -- that
+    // path only exists for constructs with a single, skippable body, like a
+    // bare `if` or a loop.
+    let exhaustive = branches.len() >= 2;
+
+    for name in names {
+        let per_branch = branches.iter()
+            .map(|branch| branch.get(&name).cloned().map(|ty| resolve_var(ty, vars)))
+            .collect();
+
+        scope.insert_name(name, join_datatypes(per_branch, exhaustive));
+    }
+}
+
+/// Resolves a branch-local `DataType::Var` to whatever it's been unified
+/// with so far. Without this, `join_datatypes` would compare two variables
+/// that happen to have resolved to the same concrete type by their (unequal)
+/// keys and wrongly conclude the branches disagree.
+fn resolve_var(ty: DataType, vars: &mut TyVarTable) -> DataType {
+    match ty {
+        DataType::Var(n) => vars.probe(TyVarKey::from_index(n)).unwrap_or(DataType::Any),
+        other => other,
+    }
+}
+
+/// Joins the datatypes a variable held across a set of branches (`None`
+/// meaning the branch never touched it, i.e. it's unset on that path) into a
+/// single datatype: the common concrete type, if every branch -- including
+/// the implicit "took none of these branches" path for non-exhaustive
+/// constructs -- agrees, otherwise an `Optional` of whatever concrete type
+/// the branches that did set it agree on.
+fn join_datatypes(mut per_branch: Vec<Option<DataType>>, exhaustive: bool) -> DataType {
+    // An `if` with no matching `else`, or a loop that runs zero times, might
+    // execute none of its branches at all, so that's an implicit extra
+    // "branch" that never sets anything. An exhaustive if/else always runs
+    // exactly one of its branches, so it has no such path -- counting one in
+    // anyway would make every variable it sets look only conditionally set.
+    if !exhaustive {
+        per_branch.push(None);
+    }
+
+    // A branch that only conditionally set it (`Optional`) doesn't count.
+    let set_on_every_branch = per_branch.iter().all(|ty| match ty {
+        Some(DataType::Optional(_)) | None => false,
+        Some(_)                            => true,
+    });
+
+    let mut concrete = per_branch.into_iter().flatten().map(|ty| match ty {
+        DataType::Optional(inner) => *inner,
+        other => other,
+    });
+
+    let first = match concrete.next() {
+        Some(ty) => ty,
+        None     => return DataType::Any,
+    };
+
+    if !concrete.all(|ty| ty == first) {
+        return DataType::Optional(Box::new(DataType::Any));
+    }
+
+    if set_on_every_branch {
+        first
+    } else {
+        DataType::Optional(Box::new(first))
+    }
+}
+
+/// Unifies `key` with whatever `expr` constrains it to be. An int literal is
+/// left alone here -- it's unifiable with either `Int` or `Bool`, so it can't
+/// pin the variable down by itself; it's only rewritten once `resolve_vars`
+/// knows which one `key` actually resolved to.
+fn unify_expr(key: TyVarKey, expr: &Expression, scope: &Scope, vars: &mut TyVarTable, name: &str, diagnostics: &mut Diagnostics) {
+    match expr {
+        Expression::LiteralInt(_) => (),
+
+        Expression::Identifier(Identifier(other)) => match lookup_captured_datatype(scope, other) {
+            Some(DataType::Var(n)) => unify_vars(key, TyVarKey::from_index(n), vars, name, diagnostics),
+
+            // Using a conditionally-set variable here needs a definitely-set
+            // value; flag it, but optimistically narrow to the inner type so
+            // one missing guard doesn't also break inference for everything
+            // downstream of it.
+            Some(DataType::Optional(inner)) => {
+                diagnostics.push(Error::PossiblyUnsetVariable { identifier: other.clone() });
+                unify_with_type(key, *inner, vars, name, diagnostics);
+            },
+
+            Some(ty) => unify_with_type(key, ty, vars, name, diagnostics),
+            None     => (),
+        },
+
+        expr => unify_with_type(key, expr.infer_datatype(scope), vars, name, diagnostics),
+    }
+}
+
+fn unify_vars(a: TyVarKey, b: TyVarKey, vars: &mut TyVarTable, name: &str, diagnostics: &mut Diagnostics) {
+    if let Err((declared_datatype, inferred_datatype)) = vars.unify_vars(a, b) {
+        vars.poison(a);
+        vars.poison(b);
+
+        diagnostics.push(Error::VarDeclareTypeMismatch {
+            identifier: name.to_string(),
+            declared_datatype,
+            inferred_datatype,
+        });
+    }
+}
+
+fn unify_with_type(key: TyVarKey, ty: DataType, vars: &mut TyVarTable, name: &str, diagnostics: &mut Diagnostics) {
+    // Nothing to learn from unifying a variable with "unknown".
+    if let DataType::Any = ty {
+        return;
+    }
+
+    if let Err((declared_datatype, inferred_datatype)) = vars.unify_concrete(key, ty) {
+        vars.poison(key);
+
+        diagnostics.push(Error::VarDeclareTypeMismatch {
+            identifier: name.to_string(),
+            declared_datatype,
+            inferred_datatype,
+        });
+    }
+}
+
+/// Walks `block` a second time, replacing every `DataType::Var` with
+/// whichever concrete type (if any) it resolved to, and rewriting the int
+/// literals that turned out to be bools along the way.
+fn resolve_vars(block: &mut Vec<Statement>, scope: &mut Scope, vars: &mut TyVarTable) {
+    for stmt in block.iter_mut() {
+        match stmt {
+            Statement::VarDeclare { datatype, identifier: Identifier(name), expression } => {
+                if let DataType::Var(n) = datatype.borrow().clone() {
+                    let resolved = vars.probe(TyVarKey::from_index(n)).unwrap_or(DataType::Any);
+
+                    datatype.replace(resolved.clone());
+                    scope.insert_name(name.clone(), resolved.clone());
+
+                    if let DataType::Bool = resolved {
+                        if let Some(expression) = expression {
+                            if let Expression::LiteralInt(v) = expression.clone().into_inner() {
+                                expression.replace(Expression::LiteralBool(v == 1));
                             }
                         }
-                    },
+                    }
+                }
+            },
 
-                    _ => (),
+            Statement::VarAssign { identifier: Identifier(name), expression } => {
+                if is_bool_ish(scope.lookup_name(name)) {
+                    if let Expression::LiteralInt(v) = expression.clone().into_inner() {
+                        expression.replace(Expression::LiteralBool(v == 1));
+                    }
+                }
+            },
+
+            Statement::MethodCall { method, arguments, .. } => match method.lookup(scope) {
+                Some((_, &DataType::Asm(ref arg_types))) |
+                Some((_, &DataType::Fun(ref arg_types))) => {
+                    for (ty, arg) in arg_types.iter().zip(arguments.iter()) {
+                        if let (DataType::Bool, Expression::LiteralInt(v)) = (ty, arg.clone().into_inner()) {
+                            arg.replace(Expression::LiteralBool(v == 1));
+                        }
+                    }
                 },
 
                 _ => (),
-            }
+            },
 
-            for mut inner_block in stmt.inner_blocks_mut() {
-                infer_datatypes(&mut inner_block, &mut scope)?;
-            }
+            _ => (),
         }
 
-        // Define the inferred types in-scope.
-        for (name, datatype) in inferred.into_iter() {
-            if let DataType::Any = datatype {
-                // ...why is this even here?
-                break
-            }
-
-            match scope.insert_name(name, datatype) {
-                Some(DataType::Any) => (),
-                Some(_) => panic!("type inferred but var has a known type already"),
-                None => (),
-            }
-
-            made_inferences = true
+        // Mirror `collect_constraints`'s per-branch scoping so a branch's own
+        // writes don't clobber the joined (possibly `Optional`) type the
+        // enclosing scope already holds for statements after this one.
+        for mut inner_block in stmt.inner_blocks_mut() {
+            scope.push();
+            resolve_vars(&mut inner_block, scope, vars);
+            scope.pop();
         }
     }
+}
 
-    Ok(())
+/// Is `datatype` `Bool`, or an `Optional` wrapping `Bool`? Used to decide
+/// whether an int literal assigned to a variable should be rewritten to a
+/// bool literal, even when the variable was only conditionally narrowed to
+/// `Bool` on some branches.
+fn is_bool_ish(datatype: Option<&DataType>) -> bool {
+    match datatype {
+        Some(DataType::Bool)             => true,
+        Some(DataType::Optional(inner))  => **inner == DataType::Bool,
+        _                                => false,
+    }
 }
 
+/// Errors accumulated while decompiling a map. A single bad inference in one
+/// function shouldn't hide every other issue in the rest of the map, so
+/// `infer_datatypes` and `fix_call_arg_capture` collect into this rather than
+/// bailing out at the first problem; `decompile_map` reports them all
+/// together once it's done.
+pub type Diagnostics = Vec<Error>;
+
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "failed to decompile bytecode: {}", _0)]
     BytecodeDecompile(#[fail(cause)] bc::Error),
 
+    #[fail(display = "failed to read bytecode from rom: {}", _0)]
+    RomRead(#[fail(cause)] crate::rom::Error),
+
     #[fail(display = "variable '{}' declared as {} but is used as {}",
         identifier, declared_datatype, inferred_datatype)]
     VarDeclareTypeMismatch {
@@ -244,6 +675,16 @@ pub enum Error {
         declared_datatype: DataType,
         inferred_datatype: DataType,
     },
+
+    #[fail(display = "call to {} has no known signature, so its captured arguments couldn't be determined", method)]
+    UnknownMethodSignature {
+        method: IdentifierOrPointer,
+    },
+
+    #[fail(display = "variable '{}' is only conditionally set, but is used here as if it's definitely set", identifier)]
+    PossiblyUnsetVariable {
+        identifier: String,
+    },
 }
 
 impl From<bc::Error> for Error {
@@ -252,6 +693,12 @@ impl From<bc::Error> for Error {
     }
 }
 
+impl From<crate::rom::Error> for Error {
+    fn from(error: crate::rom::Error) -> Error {
+        Error::RomRead(error)
+    }
+}
+
 /// A priority-queue mapping of (u32 -> String -> DataType); i.e. Scope provides
 /// lookups of pointer-to-name and name-to-datatype, preferring the current
 /// scope (see `push` and `pop`) when performing lookups.
@@ -329,3 +776,69 @@ impl Scope {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_datatypes_agrees_on_an_exhaustive_if_else() {
+        // Both arms of an if/else set the variable to the same concrete
+        // type, so it should come back out as that type, not `Optional` --
+        // there's no "ran neither branch" path to account for.
+        let per_branch = vec![Some(DataType::Bool), Some(DataType::Bool)];
+
+        assert_eq!(join_datatypes(per_branch, true), DataType::Bool);
+    }
+
+    #[test]
+    fn join_datatypes_narrows_a_non_exhaustive_branch() {
+        // A bare `if` (or a loop) might run zero times, so even though its
+        // only branch sets the variable, the merged type is `Optional`.
+        let per_branch = vec![Some(DataType::Bool)];
+
+        assert_eq!(join_datatypes(per_branch, false), DataType::Optional(Box::new(DataType::Bool)));
+    }
+
+    #[test]
+    fn join_branches_resolves_vars_before_comparing() {
+        // Two branches set the same variable through two different type
+        // variables that both happen to resolve to Bool; joining should
+        // recognise they agree instead of comparing the raw (unequal) keys.
+        let mut vars = TyVarTable::new();
+        let a = vars.new_var();
+        let b = vars.new_var();
+
+        vars.unify_concrete(a, DataType::Bool).unwrap();
+        vars.unify_concrete(b, DataType::Bool).unwrap();
+
+        let mut branch1 = HashMap::new();
+        branch1.insert("x".to_string(), DataType::Var(a.index()));
+
+        let mut branch2 = HashMap::new();
+        branch2.insert("x".to_string(), DataType::Var(b.index()));
+
+        let mut scope = Scope::new();
+        join_branches(&mut scope, vec![branch1, branch2], &mut vars);
+
+        assert_eq!(scope.lookup_name("x"), Some(&DataType::Bool));
+    }
+
+    #[test]
+    fn captured_funword_keeps_its_declared_param_type() {
+        // fix_call_arg_capture records a captured FunWord's param type in
+        // scope as it synthesizes the identifier; this is what that lookup
+        // later sees, instead of falling back to `Any`.
+        let mut scope = Scope::new();
+        scope.insert_name("funword_0".to_string(), DataType::Int);
+
+        assert_eq!(lookup_captured_datatype(&scope, "funword_0"), Some(DataType::Int));
+    }
+
+    #[test]
+    fn captured_funflag_defaults_to_bool_when_unscoped() {
+        let scope = Scope::new();
+
+        assert_eq!(lookup_captured_datatype(&scope, "funflag_0"), Some(DataType::Bool));
+    }
+}
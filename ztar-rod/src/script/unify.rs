@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use ena::unify::{InPlaceUnificationTable, UnifyKey, UnifyValue};
+use super::datatype::DataType;
+
+/// A type variable allocated for an as-yet-unresolved (`DataType::Any`)
+/// declaration. The `u32` here is the same one stored in the corresponding
+/// `DataType::Var`, so a `TyVarKey` can always be recovered from a `Scope`
+/// lookup via `TyVarKey::from_index`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TyVarKey(u32);
+
+impl UnifyKey for TyVarKey {
+    type Value = Unresolved;
+
+    fn index(&self) -> u32 {
+        self.0
+    }
+
+    fn from_index(index: u32) -> Self {
+        TyVarKey(index)
+    }
+
+    fn tag() -> &'static str {
+        "TyVarKey"
+    }
+}
+
+/// The value a `TyVarKey` unifies to: either nothing is known yet, or the
+/// variable has been constrained to a concrete `DataType`. Unifying two
+/// unresolved variables keeps them unresolved; unifying an unresolved
+/// variable with a resolved one resolves the pair; unifying two differently-
+/// resolved variables is a type error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unresolved(pub Option<DataType>);
+
+impl UnifyValue for Unresolved {
+    type Error = (DataType, DataType);
+
+    fn unify_values(a: &Self, b: &Self) -> Result<Self, Self::Error> {
+        match (&a.0, &b.0) {
+            (None, None) => Ok(Unresolved(None)),
+            (Some(ty), None) | (None, Some(ty)) => Ok(Unresolved(Some(ty.clone()))),
+            (Some(a), Some(b)) if a == b => Ok(Unresolved(Some(a.clone()))),
+            (Some(a), Some(b)) => Err((a.clone(), b.clone())),
+        }
+    }
+}
+
+/// The union-find table backing `infer_datatypes`. Each `Any` declaration
+/// gets its own key; unifying two keys merges their roots, and unifying a
+/// key with a concrete type records it against that root (or errors if the
+/// root already holds a different concrete type).
+pub struct TyVarTable {
+    table:    InPlaceUnificationTable<TyVarKey>,
+    poisoned: HashSet<u32>,
+}
+
+impl TyVarTable {
+    pub fn new() -> TyVarTable {
+        TyVarTable { table: InPlaceUnificationTable::new(), poisoned: HashSet::new() }
+    }
+
+    /// Marks `key`'s root as conflicting, so that future `probe` calls treat
+    /// it (and everything later unified with it) as still-unresolved `Any`
+    /// rather than picking one side of the conflict arbitrarily. Lets callers
+    /// carry on decompiling past a type error instead of aborting.
+    pub fn poison(&mut self, key: TyVarKey) {
+        let root = self.table.find(key).index();
+        self.poisoned.insert(root);
+    }
+
+    /// Allocates a fresh, as-yet-unresolved type variable.
+    pub fn new_var(&mut self) -> TyVarKey {
+        self.table.new_key(Unresolved(None))
+    }
+
+    /// Unifies `key` with a concrete `DataType`.
+    pub fn unify_concrete(&mut self, key: TyVarKey, ty: DataType) -> Result<(), (DataType, DataType)> {
+        self.table.unify_var_value(key, Unresolved(Some(ty)))
+    }
+
+    /// Unifies two type variables, merging their roots.
+    pub fn unify_vars(&mut self, a: TyVarKey, b: TyVarKey) -> Result<(), (DataType, DataType)> {
+        let root_a = self.table.find(a).index();
+        let root_b = self.table.find(b).index();
+        let poisoned = self.poisoned.contains(&root_a) || self.poisoned.contains(&root_b);
+
+        let result = self.table.unify_var_var(a, b);
+
+        // Whichever root survives the merge inherits the poison.
+        if poisoned && result.is_ok() {
+            self.poison(a);
+        }
+
+        result
+    }
+
+    /// Returns the concrete `DataType` `key`'s root has resolved to, if any.
+    /// Always `None` for a poisoned variable, regardless of what it last
+    /// unified with.
+    pub fn probe(&mut self, key: TyVarKey) -> Option<DataType> {
+        let root = self.table.find(key).index();
+
+        if self.poisoned.contains(&root) {
+            None
+        } else {
+            self.table.probe_value(key).0
+        }
+    }
+}